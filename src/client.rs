@@ -1,27 +1,50 @@
+use std::fs;
 use std::io;
 use std::io::Read;
+use std::io::Seek;
+use std::io::SeekFrom;
 use std::io::Write;
 use std::net;
 use std::num;
+use std::path::Path;
 
+use brotli2::write::BrotliEncoder;
 use cast::u64;
 use failure::Error;
 use failure::ResultExt;
+use flate2::write::DeflateEncoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
 use httparse;
 use httparse::EMPTY_HEADER;
 use mime;
+use mime_guess;
 use multipart::server::Multipart;
 use multipart::server::MultipartData;
 use multipart::server::MultipartField;
 use result::ResultOptionExt;
 
+use base64;
+use encoding;
+use encoding::Encoding;
 use req;
+use sha1::Sha1;
+use static_file;
+use websocket::WebSocket;
+
+/// Fixed GUID used to derive `Sec-WebSocket-Accept`, per RFC 6455 1.3.
+const WEBSOCKET_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
 
 pub struct Client {
     requested: Requested,
     addr: net::SocketAddr,
     stream: net::TcpStream,
     response: Response,
+    compress_enabled: bool,
+    encoder: Option<BodyEncoder>,
+    continue_sent: bool,
+    force_close: bool,
+    body_state: Option<BodyState>,
 }
 
 pub struct Requested {
@@ -35,6 +58,7 @@ pub struct Requested {
 pub struct Response {
     code: u16,
     message: String,
+    headers: Vec<(String, String)>,
     pub sent: bool,
 }
 
@@ -43,6 +67,7 @@ impl Default for Response {
         Response {
             code: 200,
             message: "Ok".to_string(),
+            headers: Vec::new(),
             sent: false,
         }
     }
@@ -59,9 +84,21 @@ impl Client {
             addr,
             stream,
             response: Response::default(),
+            compress_enabled: false,
+            encoder: None,
+            continue_sent: false,
+            force_close: false,
+            body_state: None,
         }
     }
 
+    /// Opt in to transparent response body compression, negotiated from the
+    /// request's `Accept-Encoding` header (gzip, deflate or brotli), as
+    /// actix/deno do. Disabled by default.
+    pub fn compress(&mut self, enabled: bool) {
+        self.compress_enabled = enabled;
+    }
+
     pub fn send_response(&mut self) -> Result<(), Error> {
         ensure!(!self.response.sent, "response already sent");
         self.write_response()?;
@@ -81,6 +118,49 @@ impl Client {
         Ok(())
     }
 
+    /// Set a response header, replacing any existing header(s) of the same name.
+    ///
+    /// Matches actix's `HttpResponseBuilder::insert`; use [`Client::add_header`] for
+    /// headers like `Set-Cookie` that are allowed to repeat.
+    pub fn set_header<K: Into<String>, V: Into<String>>(
+        &mut self,
+        name: K,
+        value: V,
+    ) -> Result<(), Error> {
+        let name = name.into();
+        self.remove_header(&name);
+        self.add_header(name, value)
+    }
+
+    /// Append a response header, keeping any existing header(s) of the same name.
+    pub fn add_header<K: Into<String>, V: Into<String>>(
+        &mut self,
+        name: K,
+        value: V,
+    ) -> Result<(), Error> {
+        ensure!(!self.response_sent(), "response already sent");
+        let name = name.into();
+        let value = value.into();
+        ensure!(
+            !name.contains(|c: char| c.is_ascii_control() || ':' == c),
+            "header name shouldn't contain control characters or a colon"
+        );
+        ensure!(
+            !value.contains(|c: char| c.is_ascii_control()),
+            "header value shouldn't contain control characters"
+        );
+        self.response.headers.push((name, value));
+        Ok(())
+    }
+
+    /// Remove all response headers matching `name`, case-insensitively.
+    pub fn remove_header<S: AsRef<str>>(&mut self, name: S) {
+        let name = name.as_ref();
+        self.response
+            .headers
+            .retain(|(key, _)| !key.eq_ignore_ascii_case(name));
+    }
+
     pub fn addr(&self) -> net::SocketAddr {
         self.addr.clone()
     }
@@ -97,6 +177,87 @@ impl Client {
         self.response.sent
     }
 
+    /// Whether this connection should stay open for another request once the
+    /// current response completes: HTTP/1.1 is keep-alive by default, HTTP/1.0
+    /// only if the client asked for it, and either is overridden by an explicit
+    /// `Connection: close` from the client (compared case-insensitively, as
+    /// actix does).
+    pub(crate) fn keep_alive(&self) -> bool {
+        if self.force_close {
+            return false;
+        }
+
+        let connection = self.request_header("Connection");
+        if connection
+            .as_ref()
+            .map(|v| v.eq_ignore_ascii_case("close"))
+            .unwrap_or(false)
+        {
+            return false;
+        }
+
+        if self.requested.version >= 1 {
+            true
+        } else {
+            connection
+                .as_ref()
+                .map(|v| v.eq_ignore_ascii_case("keep-alive"))
+                .unwrap_or(false)
+        }
+    }
+
+    /// Force the connection closed after this response, overriding whatever
+    /// `keep_alive()` would otherwise decide (e.g. once a per-connection
+    /// request cap is hit).
+    pub(crate) fn force_close(&mut self) {
+        self.force_close = true;
+    }
+
+    /// Reclaim the underlying stream, plus any bytes already read past this
+    /// request (a pipelined next request, or body bytes the handler never
+    /// consumed), so the next request on a persistent connection can be
+    /// parsed starting from exactly where this one left off. Call
+    /// `drain_body` first, or those leftover body bytes will be parsed as
+    /// the start of the next request.
+    pub(crate) fn into_parts(self) -> (net::TcpStream, Vec<u8>) {
+        (self.stream, self.requested.body_start)
+    }
+
+    /// Discard any request body the handler didn't read, so a persistent
+    /// connection can move on to the next request instead of having these
+    /// bytes parsed as part of it. A no-op if the request had no body.
+    pub(crate) fn drain_body(&mut self) -> io::Result<()> {
+        if self.body_state.is_none() {
+            // The handler answered without sending `100 Continue`, so an
+            // RFC-compliant client is waiting for that before it streams the
+            // body — reading here would block until the idle timeout rather
+            // than draining anything. Close instead of stalling the thread.
+            if self.expects_continue() && !self.continue_sent {
+                self.force_close();
+                return Ok(());
+            }
+
+            if self.request_chunked() {
+                self.body_state = Some(BodyState::Chunked {
+                    remaining: 0,
+                    done: false,
+                });
+            } else {
+                match self.content_length() {
+                    Ok(Some(len)) => self.body_state = Some(BodyState::Fixed(u64(len))),
+                    _ => return Ok(()),
+                }
+            }
+        }
+
+        let mut sink = [0u8; 8192];
+        loop {
+            if 0 == self.read_body(&mut sink)? {
+                return Ok(());
+            }
+        }
+    }
+
     /// Do a raw write to the client.
     ///
     /// If headers haven't been sent, the server won't send them, now or ever.
@@ -127,13 +288,132 @@ impl Client {
             .invert()
     }
 
+    /// Whether the client sent `Expect: 100-continue`, i.e. is waiting for a
+    /// `100 Continue` before it streams the request body.
+    pub fn expects_continue(&self) -> bool {
+        self.request_header("Expect")
+            .map(|v| v.eq_ignore_ascii_case("100-continue"))
+            .unwrap_or(false)
+    }
+
+    /// Send the `100 Continue` interim response the client is waiting for,
+    /// exactly once, if it asked for one and we haven't already sent a
+    /// response.
+    fn send_continue_if_expected(&mut self) -> io::Result<()> {
+        if self.continue_sent || self.response_sent() || !self.expects_continue() {
+            return Ok(());
+        }
+        self.continue_sent = true;
+        self.stream.write_all(b"HTTP/1.1 100 Continue\r\n\r\n")
+    }
+
+    fn request_chunked(&self) -> bool {
+        self.request_header("Transfer-Encoding")
+            .map(|v| v.eq_ignore_ascii_case("chunked"))
+            .unwrap_or(false)
+    }
+
+    /// A reader over the request body. Its consumed-so-far position lives on
+    /// `Client` itself (not the returned `BodyReader`), so calling this again
+    /// later (or `drain_body`, once the handler's done) picks up from wherever
+    /// the last reader left off, rather than re-reading from the start.
     pub fn body_reader<'a>(&'a mut self) -> Result<BodyReader<'a>, Error> {
-        let len = self
-            .content_length()?
-            .ok_or_else(|| format_err!("no content length"))?;
-        Ok(BodyReader {
-            inner: self.take(u64(len)),
-        })
+        self.send_continue_if_expected()?;
+
+        if self.body_state.is_none() {
+            self.body_state = Some(if self.request_chunked() {
+                BodyState::Chunked {
+                    remaining: 0,
+                    done: false,
+                }
+            } else {
+                let len = self
+                    .content_length()?
+                    .ok_or_else(|| format_err!("no content length"))?;
+                BodyState::Fixed(u64(len))
+            });
+        }
+
+        Ok(BodyReader { client: self })
+    }
+
+    fn read_body(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        let state = match self.body_state {
+            Some(state) => state,
+            None => return Ok(0),
+        };
+
+        match state {
+            BodyState::Fixed(remaining) => {
+                if 0 == remaining {
+                    return Ok(0);
+                }
+                let to_read = buf.len().min(remaining as usize);
+                let read = Read::read(self, &mut buf[..to_read])?;
+                self.body_state = Some(BodyState::Fixed(remaining - read as u64));
+                Ok(read)
+            }
+            BodyState::Chunked { done: true, .. } => Ok(0),
+            BodyState::Chunked { remaining, .. } => self.read_chunked_body(buf, remaining),
+        }
+    }
+
+    /// Read a single `\r\n`-terminated line (the bytes before it, without the
+    /// terminator) from the request body stream, for chunk-size and trailer
+    /// parsing.
+    fn read_body_line(&mut self) -> io::Result<Vec<u8>> {
+        let mut line = Vec::new();
+        loop {
+            let mut byte = [0u8; 1];
+            Read::read_exact(self, &mut byte)?;
+            line.push(byte[0]);
+            if line.ends_with(b"\r\n") {
+                line.truncate(line.len() - 2);
+                return Ok(line);
+            }
+        }
+    }
+
+    fn read_chunk_size(&mut self) -> io::Result<u64> {
+        let line = self.read_body_line()?;
+        let line = String::from_utf8_lossy(&line);
+        // chunk extensions (`;name=value`) aren't supported, just ignored.
+        let size = line.trim().splitn(2, ';').next().unwrap_or("");
+        u64::from_str_radix(size, 16)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))
+    }
+
+    fn read_chunked_body(&mut self, buf: &mut [u8], mut remaining: u64) -> io::Result<usize> {
+        if 0 == remaining {
+            remaining = self.read_chunk_size()?;
+            if 0 == remaining {
+                // Trailing headers (almost never sent) end with a blank line.
+                while !self.read_body_line()?.is_empty() {}
+                self.body_state = Some(BodyState::Chunked {
+                    remaining: 0,
+                    done: true,
+                });
+                return Ok(0);
+            }
+        }
+
+        let to_read = buf.len().min(remaining as usize);
+        let read = Read::read(self, &mut buf[..to_read])?;
+        remaining -= read as u64;
+        if 0 == remaining {
+            // each chunk's data is followed by a trailing `\r\n`
+            let mut crlf = [0u8; 2];
+            Read::read_exact(self, &mut crlf)?;
+        }
+        self.body_state = Some(BodyState::Chunked {
+            remaining,
+            done: false,
+        });
+        Ok(read)
     }
 
     pub fn body_parser(&mut self) -> Result<BodyParser, Error> {
@@ -155,6 +435,131 @@ impl Client {
         })
     }
 
+    /// Whether the client asked to upgrade this connection to a WebSocket,
+    /// i.e. sent `Upgrade: websocket` and `Connection: Upgrade` (the latter
+    /// possibly amongst other tokens, e.g. `keep-alive, Upgrade`), compared
+    /// case-insensitively.
+    pub fn is_websocket_upgrade(&self) -> bool {
+        let upgrade = self
+            .request_header("Upgrade")
+            .map(|v| v.eq_ignore_ascii_case("websocket"))
+            .unwrap_or(false);
+        let connection = self
+            .request_header("Connection")
+            .map(|v| {
+                v.split(',')
+                    .any(|token| token.trim().eq_ignore_ascii_case("upgrade"))
+            })
+            .unwrap_or(false);
+        upgrade && connection
+    }
+
+    /// Perform the RFC 6455 handshake and hand back a `WebSocket` framing
+    /// the same connection. Like actix's ws support, this sends the
+    /// `101 Switching Protocols` response itself (via
+    /// `write_all_overriding_headers`), so the returned `WebSocket` is a
+    /// long-lived replacement for the normal one-shot response flow: it
+    /// keeps the listener's semaphore permit for as long as the handler
+    /// holds it open.
+    pub fn accept_websocket(&mut self) -> Result<WebSocket, Error> {
+        ensure!(!self.response_sent(), "response already sent");
+        let key = self
+            .request_header("Sec-WebSocket-Key")
+            .ok_or_else(|| format_err!("websocket upgrade missing Sec-WebSocket-Key"))?;
+
+        let mut hasher = Sha1::new();
+        hasher.update(key.as_bytes());
+        hasher.update(WEBSOCKET_GUID.as_bytes());
+        let accept = base64::encode(&hasher.digest().bytes()[..]);
+
+        self.write_all_overriding_headers(
+            format!(
+                "HTTP/1.1 101 Switching Protocols\r\n\
+                 Upgrade: websocket\r\n\
+                 Connection: Upgrade\r\n\
+                 Sec-WebSocket-Accept: {}\r\n\r\n",
+                accept
+            )
+            .as_bytes(),
+        )?;
+
+        // The 101 response above doesn't carry `Connection: close`, so
+        // `keep_alive` would otherwise say this connection is still good for
+        // another HTTP request; force it closed so `handle`'s loop doesn't
+        // try to reparse WebSocket frames as one.
+        self.force_close();
+
+        // The handler's socket inherited `handle`'s keep-alive idle read
+        // timeout; a `WebSocket` is meant to stay open far longer than that
+        // between frames, so the clone we hand back needs it cleared.
+        let stream = self.stream.try_clone()?;
+        stream.set_read_timeout(None)?;
+
+        Ok(WebSocket::new(stream))
+    }
+
+    /// Serve a file, an analogue of actix's `NamedFile`: guesses
+    /// `Content-Type` from the extension, sets `Last-Modified`/`ETag`
+    /// validators, answers conditional requests with `304 Not Modified`,
+    /// and serves a single `Range: bytes=...` request with `206 Partial
+    /// Content` when asked.
+    pub fn send_file<P: AsRef<Path>>(&mut self, path: P) -> Result<(), Error> {
+        let path = path.as_ref();
+        let metadata = fs::metadata(path)?;
+        let len = metadata.len();
+        let etag = static_file::weak_etag(len, metadata.modified()?);
+
+        let not_modified = if let Some(if_none_match) = self.request_header("If-None-Match") {
+            static_file::etag_matches(&if_none_match, &etag)
+        } else if let Some(if_modified_since) = self.request_header("If-Modified-Since") {
+            httpdate::parse_http_date(&if_modified_since)
+                .map(|since| {
+                    metadata
+                        .modified()
+                        .map(|m| static_file::truncate_to_secs(m) <= since)
+                        .unwrap_or(false)
+                })
+                .unwrap_or(false)
+        } else {
+            false
+        };
+
+        self.set_header("ETag", etag)?;
+        self.set_header("Last-Modified", httpdate::fmt_http_date(metadata.modified()?))?;
+
+        if not_modified {
+            self.set_response(304, "Not Modified")?;
+            return Ok(());
+        }
+
+        let content_type = mime_guess::from_path(path).first_or_octet_stream();
+        self.set_header("Content-Type", content_type.to_string())?;
+
+        let mut file = fs::File::open(path)?;
+
+        if let Some(range) = self.request_header("Range") {
+            return match static_file::parse_byte_range(&range, len) {
+                Some((start, end)) => {
+                    self.set_response(206, "Partial Content")?;
+                    self.set_header("Content-Range", format!("bytes {}-{}/{}", start, end, len))?;
+                    self.set_header("Content-Length", (end - start + 1).to_string())?;
+                    file.seek(SeekFrom::Start(start))?;
+                    io::copy(&mut file.take(end - start + 1), self)?;
+                    Ok(())
+                }
+                None => {
+                    self.set_response(416, "Range Not Satisfiable")?;
+                    self.set_header("Content-Range", format!("bytes */{}", len))?;
+                    Ok(())
+                }
+            };
+        }
+
+        self.set_header("Content-Length", len.to_string())?;
+        io::copy(&mut file, self)?;
+        Ok(())
+    }
+
     fn send_response_if_not_already_sent(&mut self) -> io::Result<()> {
         if self.response_sent() {
             return Ok(());
@@ -163,19 +568,213 @@ impl Client {
         self.write_response()
     }
 
+    /// Whether this response is defined to carry no message body, per RFC
+    /// 7230 3.3.3: 1xx/204/304 responses, and any response to a `HEAD`
+    /// request. These can't use chunked framing (or any other
+    /// length-indicating header): the client stops reading at the header
+    /// block's blank line regardless, so a chunked terminator we send
+    /// afterwards would just be parsed as the start of the next response.
+    fn response_has_no_body(&self) -> bool {
+        let code = self.response.code;
+        1 == code / 100 || 204 == code || 304 == code || self.requested.method == "HEAD"
+    }
+
     fn write_response(&mut self) -> io::Result<()> {
         self.response.sent = true;
 
+        let no_body = self.response_has_no_body();
+
+        let coding = if !no_body && self.compress_enabled {
+            encoding::negotiate(
+                self.request_header("Accept-Encoding")
+                    .as_ref()
+                    .map(String::as_str),
+            )
+        } else {
+            Encoding::Identity
+        };
+
         write!(
             self.stream,
             "HTTP/1.{} {} {}\r\n",
             self.requested.version, self.response.code, self.response.message
         )?;
-        // TODO: headers
-        write!(self.stream, "Connection: close\r\n\r\n")?;
+        // A `Content-Length` the handler set describes the uncompressed
+        // body; once we're compressing it's wrong, so drop it rather than
+        // sending a response the client will frame incorrectly.
+        for (name, value) in &self.response.headers {
+            if coding != Encoding::Identity && name.eq_ignore_ascii_case("Content-Length") {
+                continue;
+            }
+            write!(self.stream, "{}: {}\r\n", name, value)?;
+        }
+        if let Some(token) = coding.header_value() {
+            write!(self.stream, "Content-Encoding: {}\r\n", token)?;
+        }
+        let keep_alive = self.keep_alive();
+
+        // A response can only be self-terminating without a `Content-Length`
+        // if it's chunked, so fall back to chunked framing whenever we
+        // dropped the handler's length above (compression), and also when
+        // the handler didn't set one itself and the connection is being kept
+        // alive, so dynamically generated bodies still support keep-alive.
+        let chunked = !no_body
+            && (coding != Encoding::Identity
+                || (keep_alive
+                    && !self
+                        .response
+                        .headers
+                        .iter()
+                        .any(|(name, _)| name.eq_ignore_ascii_case("Content-Length"))));
+        if chunked {
+            write!(self.stream, "Transfer-Encoding: chunked\r\n")?;
+        }
+
+        write!(
+            self.stream,
+            "Connection: {}\r\n\r\n",
+            if keep_alive { "keep-alive" } else { "close" }
+        )?;
+
+        if coding != Encoding::Identity || chunked {
+            let body_stream = if chunked {
+                BodyStream::Chunked(ChunkedWriter::new(self.stream.try_clone()?))
+            } else {
+                BodyStream::Plain(self.stream.try_clone()?)
+            };
+            self.encoder = Some(BodyEncoder::new(coding, body_stream));
+        }
+
         info!("{}: sent {}", self.addr, self.response.code);
         Ok(())
     }
+
+    /// Flush and finalise any in-flight response body encoder. Must be called
+    /// once the handler has finished writing the body, so that compressed
+    /// responses get their trailing bytes.
+    pub(crate) fn finish(&mut self) -> io::Result<()> {
+        if let Some(encoder) = self.encoder.take() {
+            encoder.finish()?;
+        }
+        Ok(())
+    }
+}
+
+/// The raw framing layer underneath any content encoding: either the plain
+/// socket (response self-terminated by connection close or `Content-Length`)
+/// or chunked transfer-coding (needed to keep a dynamically sized response
+/// self-framing on a persistent connection).
+enum BodyStream {
+    Plain(net::TcpStream),
+    Chunked(ChunkedWriter<net::TcpStream>),
+}
+
+impl BodyStream {
+    fn finish(self) -> io::Result<()> {
+        match self {
+            BodyStream::Plain(_) => Ok(()),
+            BodyStream::Chunked(chunked) => chunked.finish(),
+        }
+    }
+}
+
+impl Write for BodyStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            BodyStream::Plain(stream) => stream.write(buf),
+            BodyStream::Chunked(chunked) => chunked.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            BodyStream::Plain(stream) => stream.flush(),
+            BodyStream::Chunked(chunked) => chunked.flush(),
+        }
+    }
+}
+
+/// Wraps a stream so that each `write`/`write_all` call emits one HTTP/1.1
+/// chunk (`hex-length\r\n<data>\r\n`); `finish` emits the terminating
+/// `0\r\n\r\n` chunk.
+struct ChunkedWriter<W: Write> {
+    inner: W,
+}
+
+impl<W: Write> ChunkedWriter<W> {
+    fn new(inner: W) -> ChunkedWriter<W> {
+        ChunkedWriter { inner }
+    }
+
+    fn finish(mut self) -> io::Result<()> {
+        self.inner.write_all(b"0\r\n\r\n")
+    }
+}
+
+impl<W: Write> Write for ChunkedWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        write!(self.inner, "{:x}\r\n", buf.len())?;
+        self.inner.write_all(buf)?;
+        self.inner.write_all(b"\r\n")?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+enum BodyEncoder {
+    Identity(BodyStream),
+    Gzip(GzEncoder<BodyStream>),
+    Deflate(DeflateEncoder<BodyStream>),
+    Brotli(BrotliEncoder<BodyStream>),
+}
+
+impl BodyEncoder {
+    fn new(coding: Encoding, stream: BodyStream) -> BodyEncoder {
+        match coding {
+            Encoding::Identity => BodyEncoder::Identity(stream),
+            Encoding::Gzip => BodyEncoder::Gzip(GzEncoder::new(stream, Compression::default())),
+            Encoding::Deflate => {
+                BodyEncoder::Deflate(DeflateEncoder::new(stream, Compression::default()))
+            }
+            Encoding::Brotli => BodyEncoder::Brotli(BrotliEncoder::new(stream, 9)),
+        }
+    }
+
+    fn finish(self) -> io::Result<()> {
+        match self {
+            BodyEncoder::Identity(stream) => stream.finish(),
+            BodyEncoder::Gzip(enc) => enc.finish().and_then(BodyStream::finish),
+            BodyEncoder::Deflate(enc) => enc.finish().and_then(BodyStream::finish),
+            BodyEncoder::Brotli(enc) => enc.finish().and_then(BodyStream::finish),
+        }
+    }
+}
+
+impl Write for BodyEncoder {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            BodyEncoder::Identity(stream) => stream.write(buf),
+            BodyEncoder::Gzip(enc) => enc.write(buf),
+            BodyEncoder::Deflate(enc) => enc.write(buf),
+            BodyEncoder::Brotli(enc) => enc.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            BodyEncoder::Identity(stream) => stream.flush(),
+            BodyEncoder::Gzip(enc) => enc.flush(),
+            BodyEncoder::Deflate(enc) => enc.flush(),
+            BodyEncoder::Brotli(enc) => enc.flush(),
+        }
+    }
 }
 
 pub enum BodyParser<'c> {
@@ -233,13 +832,22 @@ impl<'c> Form<'c> {
     }
 }
 
+/// How much of the request body a `BodyReader` (or `Client::drain_body`) has
+/// left to read, carried on `Client` itself rather than the reader, so it
+/// survives a reader being dropped before the body is fully consumed.
+#[derive(Copy, Clone)]
+enum BodyState {
+    Fixed(u64),
+    Chunked { remaining: u64, done: bool },
+}
+
 pub struct BodyReader<'c> {
-    inner: io::Take<&'c mut Client>,
+    client: &'c mut Client,
 }
 
 impl<'c> Read for BodyReader<'c> {
     fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
-        self.inner.read(buf)
+        self.client.read_body(buf)
     }
 }
 
@@ -254,7 +862,7 @@ impl Read for Client {
         }
 
         let to_reply = buf.len().min(self.requested.body_start.len());
-        buf.copy_from_slice(&self.requested.body_start[..to_reply]);
+        buf[..to_reply].copy_from_slice(&self.requested.body_start[..to_reply]);
         let _ = self.requested.body_start.drain(..to_reply);
         Ok(to_reply)
     }
@@ -263,21 +871,33 @@ impl Read for Client {
 impl Write for Client {
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
         self.send_response_if_not_already_sent()?;
-        self.stream.write(buf)
+        match self.encoder {
+            Some(ref mut encoder) => encoder.write(buf),
+            None => self.stream.write(buf),
+        }
     }
 
     fn flush(&mut self) -> io::Result<()> {
-        self.stream.flush()
+        match self.encoder {
+            Some(ref mut encoder) => encoder.flush(),
+            None => self.stream.flush(),
+        }
     }
 
     fn write_all(&mut self, buf: &[u8]) -> io::Result<()> {
         self.send_response_if_not_already_sent()?;
-        self.stream.write_all(buf)
+        match self.encoder {
+            Some(ref mut encoder) => encoder.write_all(buf),
+            None => self.stream.write_all(buf),
+        }
     }
 }
 
-pub(crate) fn parse_request(stream: &mut net::TcpStream) -> Result<Requested, Error> {
-    let (header_block, body_start, headers) = req::read_headers(stream)?;
+pub(crate) fn parse_request(
+    stream: &mut net::TcpStream,
+    leftover: Vec<u8>,
+) -> Result<Requested, Error> {
+    let (header_block, body_start, headers) = req::read_headers(stream, leftover)?;
     let mut headers = vec![EMPTY_HEADER; headers];
     let mut request = httparse::Request::new(&mut headers);
     request.parse(&header_block)?;
@@ -0,0 +1,63 @@
+//! `Accept-Encoding` negotiation for response body compression.
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Encoding {
+    Identity,
+    Gzip,
+    Deflate,
+    Brotli,
+}
+
+impl Encoding {
+    /// The token used in a `Content-Encoding` response header, or `None` for identity.
+    pub fn header_value(self) -> Option<&'static str> {
+        match self {
+            Encoding::Identity => None,
+            Encoding::Gzip => Some("gzip"),
+            Encoding::Deflate => Some("deflate"),
+            Encoding::Brotli => Some("br"),
+        }
+    }
+}
+
+/// Parse an `Accept-Encoding` header value and pick the best supported coding,
+/// preferring `br`, then `gzip`, then `deflate`, falling back to identity
+/// when none match or the header is absent/empty.
+pub fn negotiate(accept_encoding: Option<&str>) -> Encoding {
+    let accept_encoding = match accept_encoding {
+        Some(value) => value,
+        None => return Encoding::Identity,
+    };
+
+    let acceptable: Vec<(String, f32)> = accept_encoding
+        .split(',')
+        .filter_map(|item| {
+            let mut parts = item.split(';');
+            let coding = parts.next()?.trim().to_ascii_lowercase();
+            if coding.is_empty() {
+                return None;
+            }
+            let q = parts
+                .find_map(|param| {
+                    let param = param.trim();
+                    if param.starts_with("q=") {
+                        param[2..].parse::<f32>().ok()
+                    } else {
+                        None
+                    }
+                })
+                .unwrap_or(1.0);
+            Some((coding, q))
+        })
+        .filter(|(_, q)| *q > 0.0)
+        .collect();
+
+    for candidate in &[Encoding::Brotli, Encoding::Gzip, Encoding::Deflate] {
+        let token = candidate.header_value().expect("non-identity candidate");
+        if acceptable.iter().any(|(coding, _)| coding == token) {
+            return *candidate;
+        }
+    }
+
+    Encoding::Identity
+}
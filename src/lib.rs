@@ -1,30 +1,51 @@
 #![feature(bufreader_buffer)]
 
+extern crate base64;
+extern crate brotli2;
 extern crate cast;
 #[macro_use]
 extern crate failure;
+extern crate flate2;
 extern crate httparse;
+extern crate httpdate;
 #[macro_use]
 extern crate log;
 extern crate mime;
+extern crate mime_guess;
 extern crate multipart;
 extern crate net2;
 extern crate result;
+extern crate sha1;
 
 mod client;
+mod encoding;
 mod req;
 mod semaphore;
+mod static_file;
+mod websocket;
 
 use std::io::Write;
 use std::net;
 use std::panic;
 use std::sync::Arc;
 use std::thread;
+use std::time::Duration;
 
 use failure::Error;
 
+/// How long a persistent connection may sit idle waiting for the next
+/// request before we give up on it and free its semaphore permit.
+const KEEP_ALIVE_IDLE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How many requests a single persistent connection may make before we
+/// close it, so one client can't hold a permit forever even if it keeps
+/// sending requests promptly.
+const MAX_REQUESTS_PER_CONNECTION: u32 = 1000;
+
 pub use client::BodyParser;
 pub use client::Client;
+pub use websocket::Message;
+pub use websocket::WebSocket;
 
 pub trait HttpRequestHandler: Send + panic::UnwindSafe {
     fn before(
@@ -102,61 +123,89 @@ fn handle(
     mut handler: impl HttpRequestHandler,
 ) -> Result<(), Error> {
     handler.before(&mut stream, &mut addr)?;
+    stream.set_read_timeout(Some(KEEP_ALIVE_IDLE_TIMEOUT))?;
 
-    let requested = match client::parse_request(&mut stream) {
-        Ok(requested) => requested,
-        Err(e) => {
-            warn!("bad request from {}: {:?}", addr, e);
-            stream.write_all(
-                b"HTTP/1.0 400 Bad Request\r\nConnection: close\r\n\r\nerr: bad request\r\n",
-            )?;
-            return Ok(());
-        }
-    };
-
-    let mut client = Client::new(requested, addr, stream);
-
-    let status = {
-        // TODO: Not sure about this `AssertUnwindSafe`; we're asserting that the `&mut` is valid,
-        // TODO: as `Client` itself already is. Code using `Client` after this point should probably
-        // TODO: be careful. But, also, what's going to happen? It's not unsafe, the worst is
-        // TODO: presumably a further panic, which we'll see in the upper error handling anyway.
-        let unwind_client = panic::AssertUnwindSafe(&mut client);
-        match panic::catch_unwind(move || handler.handle(unwind_client.0)) {
-            Ok(Ok(())) => None,
-            Ok(Err(err)) => Some(err),
-            Err(any) => Some(format_err!(
-                "panic: {}",
-                any.downcast_ref::<&str>()
-                    .map(|s| s.to_string())
-                    .or_else(|| any.downcast_ref::<String>().map(|s| s.to_string()))
-                    .unwrap_or_else(|| "Box<Any>".to_string())
-            )),
+    let mut first_request = true;
+    let mut requests_handled: u32 = 0;
+    let mut leftover = Vec::new();
+
+    loop {
+        let requested = match client::parse_request(&mut stream, leftover) {
+            Ok(requested) => requested,
+            Err(e) => {
+                if !first_request {
+                    info!("{}: connection closed (or idle timeout)", addr);
+                    return Ok(());
+                }
+                warn!("bad request from {}: {:?}", addr, e);
+                stream.write_all(
+                    b"HTTP/1.0 400 Bad Request\r\nConnection: close\r\n\r\nerr: bad request\r\n",
+                )?;
+                return Ok(());
+            }
+        };
+        first_request = false;
+        requests_handled += 1;
+
+        let mut client = Client::new(requested, addr, stream);
+        if requests_handled >= MAX_REQUESTS_PER_CONNECTION {
+            client.force_close();
         }
-    };
 
-    if !client.response_sent() {
-        if let Some(e) = status {
-            error!("{}: returning 500 for: {}", client.addr(), e);
-            client.set_response(500, "Internal Server Error")?;
-            client.write_all(b"err: internal")?;
+        let status = {
+            // TODO: Not sure about this `AssertUnwindSafe`; we're asserting that the `&mut` is valid,
+            // TODO: as `Client` itself already is. Code using `Client` after this point should probably
+            // TODO: be careful. But, also, what's going to happen? It's not unsafe, the worst is
+            // TODO: presumably a further panic, which we'll see in the upper error handling anyway.
+            let unwind_client = panic::AssertUnwindSafe(&mut client);
+            let unwind_handler = panic::AssertUnwindSafe(&mut handler);
+            match panic::catch_unwind(move || unwind_handler.0.handle(unwind_client.0)) {
+                Ok(Ok(())) => None,
+                Ok(Err(err)) => Some(err),
+                Err(any) => Some(format_err!(
+                    "panic: {}",
+                    any.downcast_ref::<&str>()
+                        .map(|s| s.to_string())
+                        .or_else(|| any.downcast_ref::<String>().map(|s| s.to_string()))
+                        .unwrap_or_else(|| "Box<Any>".to_string())
+                )),
+            }
+        };
+
+        if !client.response_sent() {
+            if let Some(e) = status {
+                error!("{}: returning 500 for: {}", client.addr(), e);
+                client.set_response(500, "Internal Server Error")?;
+                client.write_all(b"err: internal")?;
+            } else {
+                client.send_response()?;
+                info!(
+                    "{}: finished successfully, backend sent response",
+                    client.addr()
+                );
+            }
         } else {
-            client.send_response()?;
-            info!(
-                "{}: finished successfully, backend sent response",
-                client.addr()
-            );
+            if let Some(e) = status {
+                error!("{}: error after headers sent: {}", client.addr(), e);
+            } else {
+                info!(
+                    "{}: finished successfully, user sent response",
+                    client.addr()
+                );
+            }
         }
-    } else {
-        if let Some(e) = status {
-            error!("{}: error after headers sent: {}", client.addr(), e);
-        } else {
-            info!(
-                "{}: finished successfully, user sent response",
-                client.addr()
-            );
+
+        client.finish()?;
+        // Discard whatever body the handler didn't read, so it's not parsed
+        // as (the start of) the next request.
+        client.drain_body()?;
+
+        if !client.keep_alive() {
+            return Ok(());
         }
-    }
 
-    Ok(())
+        let (s, l) = client.into_parts();
+        stream = s;
+        leftover = l;
+    }
 }
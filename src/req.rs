@@ -5,19 +5,42 @@ use std::io::Read;
 use failure::Error;
 
 /// @return: (header block, body start, header count)
-pub fn read_headers<R: Read>(mut from: R) -> Result<(Vec<u8>, Vec<u8>, usize), Error> {
+///
+/// `leftover` is any bytes already read past the previous request on this
+/// connection (a pipelined next request, an undrained body, or both) that
+/// haven't been consumed yet; it's searched along with freshly read bytes,
+/// since the header terminator may already be buried inside it.
+pub fn read_headers<R: Read>(
+    mut from: R,
+    leftover: Vec<u8>,
+) -> Result<(Vec<u8>, Vec<u8>, usize), Error> {
     // We've basically rewritten half of bufreader here, would it be easier not to use it?
     let mut from = io::BufReader::new(from);
 
-    let mut ret = Vec::with_capacity(256);
-    let mut lines = 0;
+    let mut buf = leftover;
     loop {
-        from.read_until(b'\n', &mut ret)?;
-        lines += 1;
-        assert!(ret.ends_with(b"\n"));
-        if ret.ends_with(b"\n\r\n") || ret.ends_with(b"\n\n") {
-            break;
+        if let Some(end) = header_end(&buf) {
+            let mut body_start = buf.split_off(end);
+            body_start.extend_from_slice(from.buffer());
+            let lines = buf.iter().filter(|&&b| b'\n' == b).count();
+            return Ok((buf, body_start, lines));
+        }
+
+        let before = buf.len();
+        let read = from.read_until(b'\n', &mut buf)?;
+        if 0 == read && buf.len() == before {
+            bail!("connection closed before a request was sent");
         }
     }
-    Ok((ret, from.buffer().to_vec(), lines))
+}
+
+/// Find the end of the header block (the index just past the blank line
+/// terminating it) anywhere in `buf`, which may already contain bytes
+/// belonging to the body or a pipelined next request, so this can't just
+/// check whether `buf` *ends with* the terminator.
+fn header_end(buf: &[u8]) -> Option<usize> {
+    buf.windows(4)
+        .position(|w| b"\r\n\r\n" == w)
+        .map(|i| i + 4)
+        .or_else(|| buf.windows(2).position(|w| b"\n\n" == w).map(|i| i + 2))
 }
@@ -0,0 +1,82 @@
+//! Helpers for `Client::send_file`: a weak validator derived from file
+//! metadata, and single-range `Range: bytes=...` parsing.
+
+use std::time::Duration;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+
+/// A weak ETag derived from file size and modification time, as `NamedFile`
+/// does in actix: cheap to compute, good enough to detect most changes.
+pub fn weak_etag(len: u64, modified: SystemTime) -> String {
+    let mtime = modified
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    format!("W/\"{:x}-{:x}\"", len, mtime)
+}
+
+/// Whether `If-None-Match` contains a tag matching `etag`, ignoring the
+/// `W/` weak-validator prefix on either side, per RFC 7232 2.3.2.
+pub fn etag_matches(if_none_match: &str, etag: &str) -> bool {
+    let strip_weak = |tag: &str| tag.trim().trim_start_matches("W/").trim().to_string();
+    let etag = strip_weak(etag);
+    if_none_match.trim() == "*" || if_none_match.split(',').any(|tag| strip_weak(tag) == etag)
+}
+
+/// Truncate a modification time to whole-second precision, as actix does
+/// before comparing it against an `If-Modified-Since` header: `httpdate`
+/// parses to second resolution, so comparing against the untruncated mtime
+/// would almost always see it as strictly newer and never return a `304`.
+pub fn truncate_to_secs(modified: SystemTime) -> SystemTime {
+    let secs = modified
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    UNIX_EPOCH + Duration::from_secs(secs)
+}
+
+/// Parse a single-range `Range: bytes=start-end` header into an inclusive
+/// `(start, end)` byte range, clamped to a `len`-byte resource. Multi-range
+/// requests and anything we can't satisfy return `None`.
+pub fn parse_byte_range(range: &str, len: u64) -> Option<(u64, u64)> {
+    let spec = range.trim();
+    if !spec.starts_with("bytes=") {
+        return None;
+    }
+    let spec = &spec["bytes=".len()..];
+    if spec.contains(',') || 0 == len {
+        return None;
+    }
+
+    let mut parts = spec.splitn(2, '-');
+    let start = parts.next()?.trim();
+    let end = parts.next()?.trim();
+
+    match (start.is_empty(), end.is_empty()) {
+        (true, true) => None,
+        (true, false) => {
+            // suffix range: the last N bytes of the resource
+            let suffix: u64 = end.parse().ok()?;
+            if 0 == suffix {
+                return None;
+            }
+            let suffix = suffix.min(len);
+            Some((len - suffix, len - 1))
+        }
+        (false, _) => {
+            let start: u64 = start.parse().ok()?;
+            if start >= len {
+                return None;
+            }
+            let end = if end.is_empty() {
+                len - 1
+            } else {
+                end.parse::<u64>().ok()?.min(len - 1)
+            };
+            if start > end {
+                return None;
+            }
+            Some((start, end))
+        }
+    }
+}
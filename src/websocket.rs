@@ -0,0 +1,127 @@
+//! Minimal server-side WebSocket framing (RFC 6455), for use once a
+//! connection has been upgraded via `Client::accept_websocket`.
+
+use std::io::Read;
+use std::io::Write;
+use std::net;
+
+use failure::Error;
+
+/// The largest frame payload we'll allocate for, since the length in a
+/// frame header is fully client-controlled: without a cap, a single frame
+/// claiming close to `u64::MAX` bytes would abort the process trying to
+/// allocate it.
+const MAX_FRAME_LEN: u64 = 16 * 1024 * 1024;
+
+/// A single WebSocket message, as exposed to the handler after unmasking.
+pub enum Message {
+    Text(String),
+    Binary(Vec<u8>),
+    Ping(Vec<u8>),
+    Pong(Vec<u8>),
+}
+
+/// An upgraded connection, framing messages over the raw `TcpStream`.
+///
+/// This is long-lived compared to a normal request: it holds its listener
+/// semaphore permit (see `serve`) for as long as the handler keeps it open,
+/// and the underlying response was already sent via
+/// `Client::write_all_overriding_headers` during the handshake, so nothing
+/// further should be written to the original `Client`.
+pub struct WebSocket {
+    stream: net::TcpStream,
+}
+
+impl WebSocket {
+    pub(crate) fn new(stream: net::TcpStream) -> WebSocket {
+        WebSocket { stream }
+    }
+
+    /// Read the next message, unmasking client payloads as required by the
+    /// spec. Returns `Ok(None)` once the client sends a close frame. Pings
+    /// are answered with a pong automatically before being handed back, so
+    /// callers don't have to.
+    pub fn read_message(&mut self) -> Result<Option<Message>, Error> {
+        loop {
+            let mut header = [0u8; 2];
+            self.stream.read_exact(&mut header)?;
+
+            let fin = 0 != header[0] & 0b1000_0000;
+            let opcode = header[0] & 0b0000_1111;
+            let masked = 0 != header[1] & 0b1000_0000;
+            ensure!(masked, "client frames must be masked");
+            ensure!(fin, "fragmented websocket frames aren't supported");
+
+            let mut len = u64::from(header[1] & 0b0111_1111);
+            if 126 == len {
+                let mut ext = [0u8; 2];
+                self.stream.read_exact(&mut ext)?;
+                len = u64::from(u16::from_be_bytes(ext));
+            } else if 127 == len {
+                let mut ext = [0u8; 8];
+                self.stream.read_exact(&mut ext)?;
+                len = u64::from_be_bytes(ext);
+            }
+
+            ensure!(
+                len <= MAX_FRAME_LEN,
+                "frame length {} exceeds the {} byte limit",
+                len,
+                MAX_FRAME_LEN
+            );
+
+            let mut key = [0u8; 4];
+            self.stream.read_exact(&mut key)?;
+
+            let mut payload = vec![0u8; len as usize];
+            self.stream.read_exact(&mut payload)?;
+            for (i, byte) in payload.iter_mut().enumerate() {
+                *byte ^= key[i % 4];
+            }
+
+            return Ok(Some(match opcode {
+                0x1 => Message::Text(String::from_utf8(payload)?),
+                0x2 => Message::Binary(payload),
+                0x8 => return Ok(None),
+                0x9 => {
+                    self.write_frame(0xA, &payload)?;
+                    Message::Ping(payload)
+                }
+                0xA => Message::Pong(payload),
+                other => bail!("unsupported websocket opcode {}", other),
+            }));
+        }
+    }
+
+    pub fn write_text<S: AsRef<str>>(&mut self, text: S) -> Result<(), Error> {
+        self.write_frame(0x1, text.as_ref().as_bytes())
+    }
+
+    pub fn write_binary(&mut self, data: &[u8]) -> Result<(), Error> {
+        self.write_frame(0x2, data)
+    }
+
+    /// Send a close frame. The connection should not be used afterwards.
+    pub fn close(&mut self) -> Result<(), Error> {
+        self.write_frame(0x8, &[])
+    }
+
+    /// Server frames are never masked, per RFC 6455 5.1.
+    fn write_frame(&mut self, opcode: u8, payload: &[u8]) -> Result<(), Error> {
+        let mut header = vec![0b1000_0000 | opcode];
+        let len = payload.len();
+        if len < 126 {
+            header.push(len as u8);
+        } else if len <= 0xffff {
+            header.push(126);
+            header.extend_from_slice(&(len as u16).to_be_bytes());
+        } else {
+            header.push(127);
+            header.extend_from_slice(&(len as u64).to_be_bytes());
+        }
+
+        self.stream.write_all(&header)?;
+        self.stream.write_all(payload)?;
+        Ok(())
+    }
+}